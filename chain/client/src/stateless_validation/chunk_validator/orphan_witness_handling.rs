@@ -4,12 +4,17 @@
 //! processed immediately. In such cases the witness becomes an orphaned witness
 //! and it's kept in the pool until the required block arrives. Once the block
 //! arrives, all witnesses that were waiting for it can be processed.
+//!
+//! See `orphan_witness_pool` for the pool that stores the orphaned witnesses.
 
+use crate::stateless_validation::chunk_validator::orphan_witness_pool::AddOrphanWitnessResult;
 use crate::Client;
 use bytesize::ByteSize;
 use itertools::Itertools;
 use near_chain::Block;
 use near_chain_primitives::Error;
+use near_network::types::{NetworkRequests, PeerId, PeerManagerMessageRequest};
+use near_primitives::hash::CryptoHash;
 use near_primitives::stateless_validation::ChunkStateWitness;
 use near_primitives::types::{BlockHeight, EpochId};
 use std::ops::Range;
@@ -26,10 +31,56 @@ pub const ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD: Range<BlockHeight> = 2..6;
 /// TODO(#10259) - consider merging this limit with the non-orphan witness size limit.
 pub const MAX_ORPHAN_WITNESS_SIZE: ByteSize = ByteSize::mb(40);
 
+/// Upper bound on the aggregate size of all witnesses held by `OrphanStateWitnessPool` at
+/// once. This is what actually bounds the pool's memory usage against a validator sending
+/// many distinct orphan witnesses; `MAX_ORPHAN_WITNESS_SIZE` alone only bounds a single one.
+pub const DEFAULT_MAX_ORPHAN_WITNESS_POOL_SIZE: ByteSize = ByteSize::mb(400);
+
+/// Where `head_distance` falls relative to `ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD`.
+/// Split out as a pure function so the boundary behavior can be unit tested without a
+/// `Client`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeadDistanceClass {
+    TooOld,
+    TooFarAhead,
+    InRange,
+}
+
+fn classify_head_distance(head_distance: BlockHeight) -> HeadDistanceClass {
+    if head_distance < ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD.start {
+        HeadDistanceClass::TooOld
+    } else if head_distance >= ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD.end {
+        HeadDistanceClass::TooFarAhead
+    } else {
+        HeadDistanceClass::InRange
+    }
+}
+
+/// Splits witnesses released by a newly-arrived block into those already validated against
+/// `target_epoch_id` (and so need no further signature checking) and those whose
+/// admission-time epoch guess didn't match and so still need it. Split out as a pure function
+/// so the partition can be unit tested without a `Client`.
+fn partition_by_validated_epoch(
+    ready_witnesses: Vec<(ChunkStateWitness, EpochId)>,
+    target_epoch_id: &EpochId,
+) -> (Vec<ChunkStateWitness>, Vec<ChunkStateWitness>) {
+    let mut verified = Vec::with_capacity(ready_witnesses.len());
+    let mut needs_verification = Vec::new();
+    for (witness, validated_epoch_id) in ready_witnesses {
+        if validated_epoch_id == *target_epoch_id {
+            verified.push(witness);
+        } else {
+            needs_verification.push(witness);
+        }
+    }
+    (verified, needs_verification)
+}
+
 impl Client {
     pub fn handle_orphan_state_witness(
         &mut self,
         witness: ChunkStateWitness,
+        witness_source_peer_id: PeerId,
     ) -> Result<HandleOrphanWitnessOutcome, Error> {
         let chunk_header = &witness.inner.chunk_header;
         let witness_height = chunk_header.height_created();
@@ -45,21 +96,54 @@ impl Client {
         .entered();
 
         // Don't save orphaned state witnesses which are far away from the current chain head.
+        // We distinguish "too old" (stale, almost certainly spam) from "too far ahead"
+        // (may just mean we're the ones lagging behind) so callers and metrics can treat
+        // a node that's behind differently from clear spam.
         let chain_head = &self.chain.head()?;
         let head_distance = witness_height.saturating_sub(chain_head.height);
-        if !ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD.contains(&head_distance) {
+        match classify_head_distance(head_distance) {
+            HeadDistanceClass::TooOld => {
+                tracing::debug!(
+                    target: "client",
+                    head_height = chain_head.height,
+                    "Not saving an orphaned ChunkStateWitness because it's too old");
+                return Ok(HandleOrphanWitnessOutcome::TooOld {
+                    witness_height,
+                    head_height: chain_head.height,
+                });
+            }
+            HeadDistanceClass::TooFarAhead => {
+                tracing::debug!(
+                    target: "client",
+                    head_height = chain_head.height,
+                    "Not saving an orphaned ChunkStateWitness because it's too far ahead of the chain head");
+                return Ok(HandleOrphanWitnessOutcome::TooFarAhead {
+                    witness_height,
+                    head_height: chain_head.height,
+                });
+            }
+            HeadDistanceClass::InRange => {}
+        }
+
+        // Cheaply drop witnesses that are byte-for-byte identical to one we recently
+        // rejected, before paying for epoch resolution and signature verification again.
+        let witness_bytes = borsh::to_vec(&witness)?;
+        let witness_hash = CryptoHash::hash_bytes(&witness_bytes);
+        if self.chunk_validator.orphan_witness_pool.is_recently_rejected(&witness_hash) {
+            self.chunk_validator
+                .orphan_witness_pool
+                .record_rejection(witness_hash, witness_source_peer_id.clone());
             tracing::debug!(
                 target: "client",
-                head_height = chain_head.height,
-                "Not saving an orphaned ChunkStateWitness because its height isn't within the allowed height range");
-            return Ok(HandleOrphanWitnessOutcome::TooFarFromHead {
                 witness_height,
-                head_height: chain_head.height,
-            });
+                witness_shard,
+                witness_chunk = ?chunk_header.chunk_hash(),
+                "Dropping an orphaned ChunkStateWitness identical to one we recently rejected");
+            return Ok(HandleOrphanWitnessOutcome::DuplicateRejected);
         }
 
         // Don't save orphaned state witnesses which are bigger than the allowed limit.
-        let witness_size = borsh::to_vec(&witness)?.len();
+        let witness_size = witness_bytes.len();
         let witness_size_u64: u64 = witness_size.try_into().map_err(|_| {
             Error::Other(format!("Cannot convert witness size to u64: {}", witness_size))
         })?;
@@ -84,38 +168,89 @@ impl Client {
             self.epoch_manager.possible_epochs_of_height_around_tip(&chain_head, witness_height)?;
 
         // Try to validate the witness assuming that it resides in one of the possible epochs.
-        // The witness must pass validation in one of these epochs before it can be admitted to the pool.
-        let mut epoch_validation_result: Option<Result<(), Error>> = None;
+        // The witness must pass validation in one of these epochs before it can be admitted to
+        // the pool. We remember which epoch it validated against so that
+        // `process_ready_orphan_witnesses_and_clean_old` can skip redundant epoch-resolution
+        // and signature re-verification once the block arrives.
+        let mut validated_epoch_id: Option<EpochId> = None;
+        let mut epoch_validation_err: Option<Error> = None;
         for epoch_id in possible_epochs {
             match self.partially_validate_orphan_witness_in_epoch(&witness, &epoch_id) {
                 Ok(()) => {
-                    epoch_validation_result = Some(Ok(()));
+                    validated_epoch_id = Some(epoch_id);
                     break;
                 }
-                Err(err) => epoch_validation_result = Some(Err(err)),
+                Err(err) => epoch_validation_err = Some(err),
             }
         }
-        match epoch_validation_result {
-            Some(Ok(())) => {} // Validation passed in one of the possible epochs, witness can be added to the pool.
-            Some(Err(err)) => {
-                // Validation failed in all possible epochs, reject the witness
+        let validated_epoch_id = match validated_epoch_id {
+            Some(epoch_id) => epoch_id,
+            None => {
+                let err = epoch_validation_err.unwrap_or_else(|| {
+                    // possible_epochs was empty. This shouldn't happen as all epochs around the chain head are known.
+                    Error::Other(format!(
+                        "Couldn't find any matching EpochId for orphan chunk state witness with height {}",
+                        witness_height
+                    ))
+                });
+                // Validation failed in all possible epochs, reject the witness and remember
+                // it so a resubmission doesn't force us to redo this work.
+                self.chunk_validator
+                    .orphan_witness_pool
+                    .record_rejection(witness_hash, witness_source_peer_id);
                 return Err(err);
             }
-            None => {
-                // possible_epochs was empty. This shouldn't happen as all epochs around the chain head are known.
-                return Err(Error::Other(format!(
-                "Couldn't find any matching EpochId for orphan chunk state witness with height {}",
-                witness_height
-            )));
+        };
+
+        // Orphan witness is OK, try to save it to the pool.
+        let prev_block_hash = *chunk_header.prev_block_hash();
+        match self.chunk_validator.orphan_witness_pool.add_orphan_state_witness(
+            witness,
+            witness_size,
+            head_distance,
+            validated_epoch_id,
+        ) {
+            AddOrphanWitnessResult::Added => {
+                tracing::debug!(target: "client", "Saving an orphaned ChunkStateWitness to orphan pool");
+            }
+            AddOrphanWitnessResult::AddedWithEviction { evicted_count } => {
+                tracing::debug!(
+                    target: "client",
+                    evicted_count,
+                    "Saving an orphaned ChunkStateWitness to orphan pool, evicting lower-priority witnesses to make room");
+                self.request_missing_block_for_orphan_witness(prev_block_hash);
+                return Ok(HandleOrphanWitnessOutcome::Evicted { evicted_count });
+            }
+            AddOrphanWitnessResult::PoolFull => {
+                tracing::debug!(
+                    target: "client",
+                    "Not saving an orphaned ChunkStateWitness because the orphan pool is full of higher-priority witnesses");
+                return Ok(HandleOrphanWitnessOutcome::PoolFull);
             }
         }
-
-        // Orphan witness is OK, save it to the pool
-        tracing::debug!(target: "client", "Saving an orphaned ChunkStateWitness to orphan pool");
-        self.chunk_validator.orphan_witness_pool.add_orphan_state_witness(witness, witness_size);
+        self.request_missing_block_for_orphan_witness(prev_block_hash);
         Ok(HandleOrphanWitnessOutcome::SavedToPool)
     }
 
+    /// Actively requests the block that an orphaned witness is waiting for, instead of
+    /// passively hoping that it shows up through normal block propagation. De-duplicates
+    /// so that multiple orphan witnesses waiting on the same block only trigger one request;
+    /// the request is forgotten once `process_ready_orphan_witnesses_and_clean_old` consumes
+    /// the witnesses for that block (or they're cleaned up as stale).
+    fn request_missing_block_for_orphan_witness(&mut self, prev_block_hash: CryptoHash) {
+        if !self.chunk_validator.orphan_witness_pool.mark_block_requested(prev_block_hash) {
+            // A request for this block is already outstanding.
+            return;
+        }
+        tracing::debug!(
+            target: "client",
+            ?prev_block_hash,
+            "Requesting missing previous block for an orphaned ChunkStateWitness");
+        self.network_adapter.send(PeerManagerMessageRequest::NetworkRequests(
+            NetworkRequests::BlockRequest { hash: prev_block_hash },
+        ));
+    }
+
     fn partially_validate_orphan_witness_in_epoch(
         &self,
         witness: &ChunkStateWitness,
@@ -163,6 +298,20 @@ impl Client {
             .chunk_validator
             .orphan_witness_pool
             .take_state_witnesses_waiting_for_block(new_block.hash());
+        // A failure here (e.g. resolving new_block's epoch) only costs us the witnesses that
+        // were waiting for new_block; it must not skip the final-height cleanup below, which
+        // is unconditional regardless of what happened to this block's witnesses.
+        let ready_witnesses =
+            match self.verify_ready_orphan_witnesses_signatures(ready_witnesses, new_block) {
+                Ok(witnesses) => witnesses,
+                Err(err) => {
+                    tracing::error!(
+                        target: "client",
+                        ?err,
+                        "Error verifying signatures of ready orphan chunk state witnesses");
+                    Vec::new()
+                }
+            };
         for witness in ready_witnesses {
             let header = &witness.inner.chunk_header;
             tracing::debug!(
@@ -199,6 +348,77 @@ impl Client {
             .orphan_witness_pool
             .remove_witnesses_below_final_height(last_final_block.height());
     }
+
+    /// Verifies the signatures of all witnesses released by `new_block`. A witness that was
+    /// already validated (including its signature) against the epoch that `new_block` turns
+    /// out to actually belong to needs no further work, since that validation already
+    /// happened when the witness was admitted to the pool. The rest (whose epoch guess at
+    /// admission time didn't pan out, e.g. near an epoch boundary) are re-checked together
+    /// in a single batch, which is substantially faster than one at a time; batch
+    /// verification is all-or-nothing, so on failure we fall back to verifying them
+    /// individually so that only the genuinely invalid ones get dropped.
+    fn verify_ready_orphan_witnesses_signatures(
+        &self,
+        ready_witnesses: Vec<(ChunkStateWitness, EpochId)>,
+        new_block: &Block,
+    ) -> Result<Vec<ChunkStateWitness>, Error> {
+        if ready_witnesses.is_empty() {
+            return Ok(Vec::new());
+        }
+        let epoch_id = self.epoch_manager.get_epoch_id(new_block.hash())?;
+
+        let (mut verified, needs_verification) =
+            partition_by_validated_epoch(ready_witnesses, &epoch_id);
+        if needs_verification.is_empty() {
+            return Ok(verified);
+        }
+
+        let to_verify = needs_verification.iter().map(|witness| (witness, &epoch_id)).collect_vec();
+        let batch_verified = self
+            .epoch_manager
+            .verify_chunk_state_witness_signatures_batch(&to_verify)
+            .unwrap_or(false);
+        verified.extend(merge_batch_verified_with_fallback(needs_verification, batch_verified, |witness| {
+            self.epoch_manager.verify_chunk_state_witness_signature_in_epoch(witness, &epoch_id)
+        }));
+        Ok(verified)
+    }
+}
+
+/// Resolves `needs_verification` down to the witnesses that actually have a valid signature.
+/// If `batch_verified` is true, all of them passed the (all-or-nothing) batch check and are
+/// returned as-is. Otherwise each one is re-checked individually via `verify_one`, so that only
+/// the genuinely invalid ones get dropped instead of the whole batch. Split out as a pure
+/// function (parameterized over the verification closure) so the fallback behavior can be unit
+/// tested without a `Client`.
+fn merge_batch_verified_with_fallback(
+    needs_verification: Vec<ChunkStateWitness>,
+    batch_verified: bool,
+    mut verify_one: impl FnMut(&ChunkStateWitness) -> Result<bool, Error>,
+) -> Vec<ChunkStateWitness> {
+    if batch_verified {
+        return needs_verification;
+    }
+
+    tracing::warn!(
+        target: "client",
+        num_witnesses = needs_verification.len(),
+        "Batch signature verification of ready orphan witnesses failed, \
+         falling back to per-witness verification");
+    let mut verified = Vec::with_capacity(needs_verification.len());
+    for witness in needs_verification {
+        match verify_one(&witness) {
+            Ok(true) => verified.push(witness),
+            Ok(false) => tracing::warn!(
+                target: "client",
+                witness_chunk = ?witness.inner.chunk_header.chunk_hash(),
+                "Orphan witness has an invalid signature, dropping it"),
+            Err(err) => {
+                tracing::error!(target: "client", ?err, "Error verifying orphan witness signature")
+            }
+        }
+    }
+    verified
 }
 
 /// Outcome of processing an orphaned witness.
@@ -210,6 +430,99 @@ impl Client {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HandleOrphanWitnessOutcome {
     SavedToPool,
+    /// Saved to the pool, evicting `evicted_count` lower-priority witnesses to make room.
+    Evicted { evicted_count: usize },
+    /// Dropped without re-validation because it's identical to a witness we recently rejected.
+    DuplicateRejected,
+    /// Not saved because the pool is already full of witnesses at least as high-priority.
+    PoolFull,
     TooBig(usize),
-    TooFarFromHead { head_height: BlockHeight, witness_height: BlockHeight },
+    /// The witness's height is below `ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD`, i.e. it's
+    /// stale and not just waiting on a block we haven't caught up to yet.
+    TooOld { head_height: BlockHeight, witness_height: BlockHeight },
+    /// The witness's height is above `ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD`. Unlike
+    /// `TooOld`, this can simply mean our node is lagging behind the network.
+    TooFarAhead { head_height: BlockHeight, witness_height: BlockHeight },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_witness(height: BlockHeight, prev_block_hash: CryptoHash) -> ChunkStateWitness {
+        ChunkStateWitness::new_dummy(height, 0, prev_block_hash)
+    }
+
+    fn epoch_id(seed: u8) -> EpochId {
+        EpochId(CryptoHash::hash_bytes(&[seed]))
+    }
+
+    #[test]
+    fn head_distance_just_below_start_is_too_old() {
+        let start = ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD.start;
+        assert_eq!(classify_head_distance(start - 1), HeadDistanceClass::TooOld);
+        assert_eq!(classify_head_distance(start), HeadDistanceClass::InRange);
+    }
+
+    #[test]
+    fn head_distance_just_above_end_is_too_far_ahead() {
+        let end = ALLOWED_ORPHAN_WITNESS_DISTANCE_FROM_HEAD.end;
+        assert_eq!(classify_head_distance(end - 1), HeadDistanceClass::InRange);
+        assert_eq!(classify_head_distance(end), HeadDistanceClass::TooFarAhead);
+    }
+
+    #[test]
+    fn partition_separates_matching_and_mismatched_validated_epoch() {
+        let prev_block_hash = CryptoHash::hash_bytes(b"prev");
+        let matching = make_witness(10, prev_block_hash);
+        let mismatched = make_witness(11, prev_block_hash);
+        let ready = vec![(matching.clone(), epoch_id(1)), (mismatched.clone(), epoch_id(2))];
+
+        let (verified, needs_verification) = partition_by_validated_epoch(ready, &epoch_id(1));
+        assert_eq!(verified, vec![matching]);
+        assert_eq!(needs_verification, vec![mismatched]);
+    }
+
+    #[test]
+    fn batch_verified_skips_per_witness_fallback() {
+        let prev_block_hash = CryptoHash::hash_bytes(b"prev");
+        let witnesses = vec![make_witness(10, prev_block_hash), make_witness(11, prev_block_hash)];
+
+        let result = merge_batch_verified_with_fallback(witnesses.clone(), true, |_| {
+            panic!("verify_one shouldn't be called once the batch already succeeded")
+        });
+        assert_eq!(result, witnesses);
+    }
+
+    #[test]
+    fn batch_failure_falls_back_to_dropping_only_the_invalid_witness() {
+        let prev_block_hash = CryptoHash::hash_bytes(b"prev");
+        let valid = make_witness(10, prev_block_hash);
+        let invalid = make_witness(11, prev_block_hash);
+        let witnesses = vec![valid.clone(), invalid.clone()];
+
+        let result =
+            merge_batch_verified_with_fallback(witnesses, false, |witness| Ok(*witness == valid));
+        assert_eq!(result, vec![valid]);
+    }
+
+    #[test]
+    fn batch_failure_fallback_keeps_all_witnesses_if_all_are_valid() {
+        let prev_block_hash = CryptoHash::hash_bytes(b"prev");
+        let witnesses = vec![make_witness(10, prev_block_hash), make_witness(11, prev_block_hash)];
+
+        let result = merge_batch_verified_with_fallback(witnesses.clone(), false, |_| Ok(true));
+        assert_eq!(result, witnesses);
+    }
+
+    #[test]
+    fn fallback_verification_error_drops_the_witness() {
+        let prev_block_hash = CryptoHash::hash_bytes(b"prev");
+        let witnesses = vec![make_witness(10, prev_block_hash)];
+
+        let result = merge_batch_verified_with_fallback(witnesses, false, |_| {
+            Err(Error::Other("signature check failed".to_string()))
+        });
+        assert!(result.is_empty());
+    }
 }
@@ -0,0 +1,464 @@
+//! Pool of orphaned chunk state witnesses, i.e. witnesses that arrived before
+//! their previous block was available. Witnesses are kept here until the
+//! required block shows up (see `process_ready_orphan_witnesses_and_clean_old`
+//! in `orphan_witness_handling.rs`), or until they become too old to be useful.
+
+use crate::stateless_validation::chunk_validator::orphan_witness_handling::DEFAULT_MAX_ORPHAN_WITNESS_POOL_SIZE;
+use lru::LruCache;
+use near_network::types::PeerId;
+use near_o11y::metrics::{try_create_int_counter, IntCounter};
+use near_primitives::hash::CryptoHash;
+use near_primitives::stateless_validation::ChunkStateWitness;
+use near_primitives::types::{BlockHeight, EpochId};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+
+/// How many recently-rejected witness hashes we remember, so that a resubmission of the
+/// same invalid witness can be dropped before paying for epoch resolution and signature
+/// verification again.
+const REJECTED_WITNESS_CACHE_SIZE: usize = 10_000;
+
+/// How many distinct peers' rejection counts we track at once. Bounded for the same reason
+/// as `REJECTED_WITNESS_CACHE_SIZE`: an attacker churning through peer identities shouldn't
+/// be able to grow this without bound.
+const REJECTIONS_BY_PEER_CACHE_SIZE: usize = 10_000;
+
+/// An orphaned witness together with the bookkeeping needed to manage it while
+/// it sits in the pool.
+struct CacheEntry {
+    witness: ChunkStateWitness,
+    witness_size: usize,
+    /// How far the witness's height was from the chain head when it was admitted.
+    /// Used to prioritize evictions: witnesses further from the head are less likely
+    /// to ever become useful, so they're evicted first.
+    head_distance: BlockHeight,
+    /// Monotonically increasing insertion index, used to break ties between witnesses
+    /// with the same `head_distance` by evicting the oldest one first.
+    inserted_at: u64,
+    /// The epoch this witness was fully validated against (shard, assignment and signature)
+    /// before being admitted to the pool. Lets `process_ready_orphan_witnesses_and_clean_old`
+    /// skip redundant re-validation once the block arrives and the real epoch is known, as
+    /// long as it matches this one.
+    validated_epoch_id: EpochId,
+}
+
+/// Result of trying to add a witness to a pool that enforces a total-bytes budget.
+pub enum AddOrphanWitnessResult {
+    /// The witness was added, evicting no one.
+    Added,
+    /// The witness was added, evicting the given number of lower-priority residents to make room.
+    AddedWithEviction { evicted_count: usize },
+    /// The witness couldn't be added because there wasn't enough lower-priority residents
+    /// to evict to make room for it.
+    PoolFull,
+}
+
+/// Holds chunk state witnesses whose previous block hasn't arrived yet, keyed
+/// by the hash of the block they're waiting for. The pool enforces a ceiling on its
+/// total memory usage: once full, the lowest-priority witnesses (furthest from the
+/// chain head, then oldest) are evicted to make room for new ones.
+pub struct OrphanStateWitnessPool {
+    witnesses: HashMap<CryptoHash, Vec<CacheEntry>>,
+    /// Blocks for which we've already sent out a targeted block request, so we
+    /// don't flood the network with duplicate requests while one is in flight.
+    requested_blocks: HashSet<CryptoHash>,
+    /// Sum of `witness_size` across all witnesses currently in the pool.
+    total_bytes: usize,
+    /// The pool won't grow past this many aggregate bytes; see `AddOrphanWitnessResult`.
+    max_total_bytes: usize,
+    next_insertion_id: u64,
+    /// Hashes of recently-rejected witnesses, so a byte-for-byte resubmission is dropped
+    /// cheaply instead of being re-validated from scratch.
+    rejected_witnesses: LruCache<CryptoHash, ()>,
+    /// How many witnesses we've rejected from each sending peer, including cheaply-dropped
+    /// duplicates. Exposed so the caller can feed it into peer misbehavior scoring.
+    rejections_by_peer: LruCache<PeerId, u64>,
+}
+
+impl OrphanStateWitnessPool {
+    pub fn new(max_total_bytes: usize) -> Self {
+        Self {
+            witnesses: HashMap::new(),
+            requested_blocks: HashSet::new(),
+            total_bytes: 0,
+            max_total_bytes,
+            next_insertion_id: 0,
+            rejected_witnesses: LruCache::new(NonZeroUsize::new(REJECTED_WITNESS_CACHE_SIZE).unwrap()),
+            rejections_by_peer: LruCache::new(
+                NonZeroUsize::new(REJECTIONS_BY_PEER_CACHE_SIZE).unwrap(),
+            ),
+        }
+    }
+
+    /// Returns whether `witness_hash` matches a witness we rejected recently.
+    pub fn is_recently_rejected(&self, witness_hash: &CryptoHash) -> bool {
+        self.rejected_witnesses.contains(witness_hash)
+    }
+
+    /// Remembers that a witness was rejected, and bumps the rejection count for the peer
+    /// that sent it. Callers should use `rejection_count_for_peer` to feed a misbehavior/
+    /// peer-scoring signal.
+    pub fn record_rejection(&mut self, witness_hash: CryptoHash, source_peer_id: PeerId) {
+        self.rejected_witnesses.put(witness_hash, ());
+        let count = self.rejections_by_peer.get(&source_peer_id).copied().unwrap_or(0);
+        self.rejections_by_peer.put(source_peer_id, count + 1);
+    }
+
+    /// Number of witnesses we've rejected from `peer_id` so far (within the tracked window).
+    pub fn rejection_count_for_peer(&mut self, peer_id: &PeerId) -> u64 {
+        self.rejections_by_peer.get(peer_id).copied().unwrap_or(0)
+    }
+
+    /// Adds an orphaned witness to the pool, keyed by the previous block hash it's waiting
+    /// for. `head_distance` is the witness's height minus the chain head's height at the time
+    /// it was admitted, and is used to prioritize it relative to other residents if the pool
+    /// needs to evict something to make room.
+    pub fn add_orphan_state_witness(
+        &mut self,
+        witness: ChunkStateWitness,
+        witness_size: usize,
+        head_distance: BlockHeight,
+        validated_epoch_id: EpochId,
+    ) -> AddOrphanWitnessResult {
+        let evicted_count = if self.total_bytes + witness_size > self.max_total_bytes {
+            match self.evict_to_fit(witness_size, head_distance) {
+                Some(evicted_count) => evicted_count,
+                None => return AddOrphanWitnessResult::PoolFull,
+            }
+        } else {
+            0
+        };
+
+        let prev_block_hash = *witness.inner.chunk_header.prev_block_hash();
+        let inserted_at = self.next_insertion_id;
+        self.next_insertion_id += 1;
+        self.total_bytes += witness_size;
+        self.witnesses.entry(prev_block_hash).or_default().push(CacheEntry {
+            witness,
+            witness_size,
+            head_distance,
+            inserted_at,
+            validated_epoch_id,
+        });
+
+        if evicted_count > 0 {
+            AddOrphanWitnessResult::AddedWithEviction { evicted_count }
+        } else {
+            AddOrphanWitnessResult::Added
+        }
+    }
+
+    /// Evicts residents that are lower priority than the incoming witness — a strictly
+    /// greater `head_distance`, or the same `head_distance` but an older insertion (the
+    /// incoming witness, not yet inserted, is implicitly the newest of its tier) — until
+    /// `new_witness_size` bytes of room are freed. Returns `None` if there aren't enough
+    /// lower-priority residents to do so.
+    fn evict_to_fit(&mut self, new_witness_size: usize, new_head_distance: BlockHeight) -> Option<usize> {
+        let needed = (self.total_bytes + new_witness_size).saturating_sub(self.max_total_bytes);
+
+        let mut candidates: Vec<(CryptoHash, usize, BlockHeight, u64, usize)> = self
+            .witnesses
+            .iter()
+            .flat_map(|(block_hash, entries)| {
+                entries.iter().enumerate().map(move |(index, entry)| {
+                    (*block_hash, index, entry.head_distance, entry.inserted_at, entry.witness_size)
+                })
+            })
+            .collect();
+        // Lowest priority first: greatest head_distance, then oldest insertion.
+        candidates.sort_by(|a, b| b.2.cmp(&a.2).then(a.3.cmp(&b.3)));
+
+        let mut to_evict = Vec::new();
+        let mut freed = 0usize;
+        for (block_hash, index, head_distance, _inserted_at, witness_size) in candidates {
+            if freed >= needed {
+                break;
+            }
+            if head_distance < new_head_distance {
+                // Everything left is strictly higher priority than the new witness (closer
+                // to the head), so we can't fairly evict it on the new witness's behalf.
+                break;
+            }
+            to_evict.push((block_hash, index));
+            freed += witness_size;
+        }
+        if freed < needed {
+            return None;
+        }
+
+        let evicted_count = to_evict.len();
+        // Evict in reverse index order per block so earlier removals don't shift the
+        // indices of the ones still queued for eviction.
+        to_evict.sort_by(|a, b| b.1.cmp(&a.1));
+        for (block_hash, index) in to_evict {
+            if let Some(entries) = self.witnesses.get_mut(&block_hash) {
+                let entry = entries.remove(index);
+                self.total_bytes -= entry.witness_size;
+                if entries.is_empty() {
+                    self.witnesses.remove(&block_hash);
+                    self.requested_blocks.remove(&block_hash);
+                }
+            }
+        }
+        ORPHAN_WITNESS_POOL_EVICTIONS_TOTAL.inc_by(evicted_count as u64);
+        Some(evicted_count)
+    }
+
+    /// Takes out all witnesses that were waiting for `block_hash`, together with the epoch
+    /// each one was validated against, and forgets that a block request was outstanding for
+    /// it, so a future orphan can request it again.
+    pub fn take_state_witnesses_waiting_for_block(
+        &mut self,
+        block_hash: &CryptoHash,
+    ) -> Vec<(ChunkStateWitness, EpochId)> {
+        self.requested_blocks.remove(block_hash);
+        let entries = self.witnesses.remove(block_hash).unwrap_or_default();
+        entries
+            .into_iter()
+            .map(|entry| {
+                self.total_bytes -= entry.witness_size;
+                (entry.witness, entry.validated_epoch_id)
+            })
+            .collect()
+    }
+
+    /// Removes all witnesses whose own height is at or below `final_height`; they're stale
+    /// and won't ever be processed, so we can drop them to save memory.
+    pub fn remove_witnesses_below_final_height(&mut self, final_height: BlockHeight) {
+        let mut freed = 0usize;
+        self.witnesses.retain(|_, entries| {
+            entries.retain(|entry| {
+                let keep = entry.witness.inner.chunk_header.height_created() > final_height;
+                if !keep {
+                    freed += entry.witness_size;
+                }
+                keep
+            });
+            !entries.is_empty()
+        });
+        self.total_bytes -= freed;
+        let witnesses = &self.witnesses;
+        self.requested_blocks.retain(|block_hash| witnesses.contains_key(block_hash));
+    }
+
+    /// Marks `block_hash` as having an outstanding block request. Returns `true` if this is
+    /// a new request the caller should actually send, or `false` if one was already in
+    /// flight and the caller should skip sending a duplicate.
+    pub fn mark_block_requested(&mut self, block_hash: CryptoHash) -> bool {
+        self.requested_blocks.insert(block_hash)
+    }
+}
+
+impl Default for OrphanStateWitnessPool {
+    /// Builds a pool bounded by `DEFAULT_MAX_ORPHAN_WITNESS_POOL_SIZE`, the size `ChunkValidator`
+    /// should construct its pool with absent a more specific configured value.
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ORPHAN_WITNESS_POOL_SIZE.as_u64() as usize)
+    }
+}
+
+pub(crate) static ORPHAN_WITNESS_POOL_EVICTIONS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    try_create_int_counter(
+        "near_orphan_chunk_state_witness_pool_evictions_total",
+        "Number of orphaned chunk state witnesses evicted from OrphanStateWitnessPool to make room for higher-priority ones",
+    )
+    .unwrap()
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_witness(height: BlockHeight, prev_block_hash: CryptoHash) -> ChunkStateWitness {
+        ChunkStateWitness::new_dummy(height, 0, prev_block_hash)
+    }
+
+    fn block_hash(seed: u8) -> CryptoHash {
+        CryptoHash::hash_bytes(&[seed])
+    }
+
+    #[test]
+    fn add_without_exceeding_budget_does_not_evict() {
+        let mut pool = OrphanStateWitnessPool::new(1_000);
+        let witness = make_witness(10, block_hash(1));
+        let result = pool.add_orphan_state_witness(witness, 100, 3, EpochId::default());
+        assert!(matches!(result, AddOrphanWitnessResult::Added));
+        assert_eq!(pool.total_bytes, 100);
+    }
+
+    #[test]
+    fn eviction_picks_lowest_priority_resident_first() {
+        let mut pool = OrphanStateWitnessPool::new(250);
+        // Two lower-priority residents at the same head_distance tier, oldest first.
+        pool.add_orphan_state_witness(
+            make_witness(10, block_hash(1)),
+            100,
+            5,
+            EpochId::default(),
+        );
+        pool.add_orphan_state_witness(
+            make_witness(11, block_hash(2)),
+            100,
+            5,
+            EpochId::default(),
+        );
+
+        // A higher-priority witness that needs 100 bytes of room to fit.
+        let result = pool.add_orphan_state_witness(
+            make_witness(12, block_hash(3)),
+            100,
+            2,
+            EpochId::default(),
+        );
+        match result {
+            AddOrphanWitnessResult::AddedWithEviction { evicted_count } => {
+                assert_eq!(evicted_count, 1)
+            }
+            AddOrphanWitnessResult::Added | AddOrphanWitnessResult::PoolFull => {
+                panic!("expected AddedWithEviction")
+            }
+        }
+        assert_eq!(pool.total_bytes, 200);
+        // The oldest of the two equal-priority residents is the one that got evicted.
+        assert!(!pool.witnesses.contains_key(&block_hash(1)));
+        assert!(pool.witnesses.contains_key(&block_hash(2)));
+        assert!(pool.witnesses.contains_key(&block_hash(3)));
+    }
+
+    #[test]
+    fn pool_full_of_higher_priority_residents_rejects_without_partial_mutation() {
+        let mut pool = OrphanStateWitnessPool::new(150);
+        pool.add_orphan_state_witness(
+            make_witness(10, block_hash(1)),
+            100,
+            2,
+            EpochId::default(),
+        );
+
+        // The newcomer is further from the head than the resident, so it can't evict it.
+        let result = pool.add_orphan_state_witness(
+            make_witness(11, block_hash(2)),
+            100,
+            3,
+            EpochId::default(),
+        );
+        assert!(matches!(result, AddOrphanWitnessResult::PoolFull));
+        assert_eq!(pool.total_bytes, 100);
+        assert!(pool.witnesses.contains_key(&block_hash(1)));
+        assert!(!pool.witnesses.contains_key(&block_hash(2)));
+    }
+
+    #[test]
+    fn eviction_emptying_a_block_bucket_clears_its_request_tracking() {
+        let mut pool = OrphanStateWitnessPool::new(150);
+        let evicted_block_hash = block_hash(1);
+        pool.add_orphan_state_witness(
+            make_witness(10, evicted_block_hash),
+            100,
+            5,
+            EpochId::default(),
+        );
+        assert!(pool.mark_block_requested(evicted_block_hash));
+
+        pool.add_orphan_state_witness(
+            make_witness(11, block_hash(2)),
+            100,
+            2,
+            EpochId::default(),
+        );
+
+        assert!(!pool.witnesses.contains_key(&evicted_block_hash));
+        assert!(!pool.requested_blocks.contains(&evicted_block_hash));
+        assert_eq!(pool.total_bytes, 100);
+    }
+
+    #[test]
+    fn duplicate_rejection_cache_tracks_hash_and_peer() {
+        let mut pool = OrphanStateWitnessPool::new(1_000);
+        let witness_hash = CryptoHash::hash_bytes(b"witness");
+        let peer_id = PeerId::random();
+        assert!(!pool.is_recently_rejected(&witness_hash));
+
+        pool.record_rejection(witness_hash, peer_id.clone());
+        assert!(pool.is_recently_rejected(&witness_hash));
+        assert_eq!(pool.rejection_count_for_peer(&peer_id), 1);
+
+        pool.record_rejection(witness_hash, peer_id.clone());
+        assert_eq!(pool.rejection_count_for_peer(&peer_id), 2);
+    }
+
+    #[test]
+    fn rejection_counts_are_tracked_independently_per_peer() {
+        let mut pool = OrphanStateWitnessPool::new(1_000);
+        let witness_hash = CryptoHash::hash_bytes(b"witness");
+        let first_peer = PeerId::random();
+        let second_peer = PeerId::random();
+
+        pool.record_rejection(witness_hash, first_peer.clone());
+        pool.record_rejection(witness_hash, first_peer.clone());
+        pool.record_rejection(witness_hash, second_peer.clone());
+
+        assert_eq!(pool.rejection_count_for_peer(&first_peer), 2);
+        assert_eq!(pool.rejection_count_for_peer(&second_peer), 1);
+    }
+
+    #[test]
+    fn resubmitted_duplicate_is_recognized_without_touching_the_main_pool() {
+        // Mirrors the short-circuit in handle_orphan_state_witness: a caller that sees
+        // is_recently_rejected return true returns DuplicateRejected immediately, without
+        // ever calling add_orphan_state_witness (i.e. without redoing epoch/signature
+        // validation). Here we just assert the cache-side half of that contract: recording
+        // a rejection never touches the witnesses/total_bytes the real admission path owns.
+        let mut pool = OrphanStateWitnessPool::new(1_000);
+        let witness_hash = CryptoHash::hash_bytes(b"witness");
+        let peer_id = PeerId::random();
+
+        pool.record_rejection(witness_hash, peer_id.clone());
+        assert!(pool.is_recently_rejected(&witness_hash));
+        // A resubmission keeps hitting the short-circuit instead of ever being admitted.
+        assert!(pool.is_recently_rejected(&witness_hash));
+        assert_eq!(pool.total_bytes, 0);
+        assert!(pool.witnesses.is_empty());
+    }
+
+    #[test]
+    fn mark_block_requested_suppresses_duplicate_requests() {
+        let mut pool = OrphanStateWitnessPool::new(1_000);
+        let prev_block_hash = block_hash(1);
+
+        // The first witness waiting for this block triggers a request.
+        assert!(pool.mark_block_requested(prev_block_hash));
+        // A second witness waiting for the same block finds one already in flight.
+        assert!(!pool.mark_block_requested(prev_block_hash));
+
+        // Once the block arrives and its witnesses are taken out, the request is forgotten,
+        // so a future orphan waiting on the same block (hash reuse aside) can request it again.
+        pool.take_state_witnesses_waiting_for_block(&prev_block_hash);
+        assert!(pool.mark_block_requested(prev_block_hash));
+    }
+
+    #[test]
+    fn validated_epoch_id_round_trips_through_the_pool() {
+        let mut pool = OrphanStateWitnessPool::new(1_000);
+        let prev_block_hash = block_hash(1);
+        let epoch_id = EpochId(CryptoHash::hash_bytes(b"epoch"));
+
+        pool.add_orphan_state_witness(
+            make_witness(10, prev_block_hash),
+            100,
+            3,
+            epoch_id,
+        );
+
+        let ready = pool.take_state_witnesses_waiting_for_block(&prev_block_hash);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].1, epoch_id);
+    }
+
+    #[test]
+    fn default_pool_is_bounded_by_the_configured_default_size() {
+        let pool = OrphanStateWitnessPool::default();
+        assert_eq!(pool.max_total_bytes, DEFAULT_MAX_ORPHAN_WITNESS_POOL_SIZE.as_u64() as usize);
+    }
+}